@@ -1,27 +1,387 @@
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use axum::{extract::State, routing::get, Router};
 use clap::Parser;
 use libp2p::{
-    autonat, core,
+    autonat, connection_limits, core, dcutr,
     futures::StreamExt,
     identify,
     identity::{self, Keypair},
     kad::{self, InboundRequest, QueryResult, Record},
+    metrics::{Metrics, Recorder},
     noise, ping,
-    swarm::{self, NetworkBehaviour, SwarmEvent},
-    tcp, websocket, yamux, Multiaddr, Swarm, Transport,
+    rendezvous, relay,
+    swarm::{DialError, ListenError, NetworkBehaviour, SwarmEvent},
+    tcp, yamux, Multiaddr, PeerId, Swarm,
 };
 use lp2p::extract_peer_id;
+use prometheus_client::{encoding::text::encode, metrics::counter::Counter, registry::Registry};
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
+/// Minimum time between automatic `kad::Mode` switches, to avoid thrashing
+/// the mode on every AutoNAT probe while reachability is flapping.
+const KAD_MODE_DEBOUNCE: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum KadModeArg {
+    /// Follow AutoNAT: advertise `Server` once reachability is confirmed,
+    /// fall back to `Client` otherwise.
+    Auto,
+    /// Always advertise as a DHT server.
+    Server,
+    /// Always stay in client mode.
+    Client,
+}
+
 #[derive(Clone, Debug, clap::Parser)]
 struct App {
-    #[arg(short='l', value_delimiter=',', num_args=1.., default_value = "/ip4/0.0.0.0/tcp/64001,/ip4/0.0.0.0/tcp/64002/ws")]
+    #[arg(short='l', value_delimiter=',', num_args=1.., default_value = "/ip4/0.0.0.0/tcp/64001,/ip4/0.0.0.0/tcp/64002/ws,/ip4/0.0.0.0/udp/64001/quic-v1")]
     listen_addrs: Vec<Multiaddr>,
 
     #[arg(short='b', value_delimiter=',', num_args=1..)]
     bootnodes: Vec<Multiaddr>,
+
+    /// Controls how `kad::Mode` is chosen. `auto` follows AutoNAT reachability,
+    /// only advertising as a server once an external address has been confirmed.
+    #[arg(long, value_enum, default_value_t = KadModeArg::Auto)]
+    kad_mode: KadModeArg,
+
+    /// Relays to fall back on for hole punching when AutoNAT reports us as
+    /// private. We dial each one and listen on its address with
+    /// `/p2p-circuit` appended.
+    #[arg(long = "relays", value_delimiter = ',', num_args = 0..)]
+    relays: Vec<Multiaddr>,
+
+    /// A rendezvous point to register with and discover other DHT peers
+    /// through, as an alternative to static bootnodes.
+    #[arg(long)]
+    rendezvous: Option<Multiaddr>,
+
+    /// Namespace to register under / discover peers in at the rendezvous point.
+    #[arg(long, default_value = "polka-test")]
+    rendezvous_namespace: String,
+
+    /// How often to re-register with the rendezvous point, since
+    /// registrations expire after their TTL.
+    #[arg(long, default_value = "60", value_parser = parse_seconds)]
+    rendezvous_reregister_interval: Duration,
+
+    /// How often to re-run `kad.bootstrap()` and a random-walk refresh of
+    /// the routing table, in addition to the one-shot bootstrap triggered
+    /// by the first connection.
+    #[arg(long, default_value = "300", value_parser = parse_seconds)]
+    bootstrap_interval: Duration,
+
+    /// Disable the random-walk routing-table refresh that runs alongside
+    /// the periodic bootstrap.
+    #[arg(long)]
+    disable_random_walk: bool,
+
+    /// Address to serve Prometheus metrics on.
+    #[arg(long, default_value = "127.0.0.1:9090")]
+    metrics_addr: SocketAddr,
+
+    /// Maximum number of established incoming connections.
+    #[arg(long)]
+    max_established_incoming: Option<u32>,
+
+    /// Maximum number of established outgoing connections.
+    #[arg(long)]
+    max_established_outgoing: Option<u32>,
+
+    /// Maximum number of pending incoming connections.
+    #[arg(long)]
+    max_pending_incoming: Option<u32>,
+
+    /// Maximum number of pending outgoing connections.
+    #[arg(long)]
+    max_pending_outgoing: Option<u32>,
+
+    /// Maximum number of established connections per peer, matching
+    /// production nodes which keep at most one connection per peer.
+    #[arg(long, default_value_t = 1)]
+    max_per_peer: u32,
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, std::num::ParseIntError> {
+    s.parse().map(Duration::from_secs)
+}
+
+/// Tracks the desired Kademlia mode and debounces AutoNAT flapping so we
+/// don't thrash `kad.set_mode` on every probe.
+struct KadModeManager {
+    policy: KadModeArg,
+    current: kad::Mode,
+    last_switch: Instant,
+}
+
+impl KadModeManager {
+    fn new(policy: KadModeArg) -> Self {
+        let current = match policy {
+            KadModeArg::Server => kad::Mode::Server,
+            KadModeArg::Auto | KadModeArg::Client => kad::Mode::Client,
+        };
+        Self {
+            policy,
+            current,
+            last_switch: Instant::now() - KAD_MODE_DEBOUNCE,
+        }
+    }
+
+    /// Called on every AutoNAT status transition; applies the new mode to
+    /// `kad` unless we're pinned to a fixed policy or still debouncing.
+    fn on_nat_status(&mut self, swarm: &mut Swarm<Behaviour>, status: autonat::NatStatus) {
+        if self.policy != KadModeArg::Auto {
+            return;
+        }
+
+        let desired = match status {
+            autonat::NatStatus::Public(_) => kad::Mode::Server,
+            autonat::NatStatus::Private => kad::Mode::Client,
+            autonat::NatStatus::Unknown => return,
+        };
+
+        if desired == self.current {
+            return;
+        }
+
+        if self.last_switch.elapsed() < KAD_MODE_DEBOUNCE {
+            tracing::debug!("Ignoring Kademlia mode flap to {desired:?}, still debouncing");
+            return;
+        }
+
+        tracing::info!("Switching Kademlia mode to {desired:?}");
+        swarm.behaviour_mut().kad.set_mode(Some(desired));
+        self.current = desired;
+        self.last_switch = Instant::now();
+    }
+}
+
+/// Dials every configured relay and registers a circuit listen address on
+/// each the first time AutoNAT reports us as unreachable directly.
+struct RelayManager {
+    relays: Vec<Multiaddr>,
+    dialed: bool,
+}
+
+impl RelayManager {
+    fn new(relays: Vec<Multiaddr>) -> Self {
+        Self {
+            relays,
+            dialed: false,
+        }
+    }
+
+    fn on_nat_status(&mut self, swarm: &mut Swarm<Behaviour>, status: autonat::NatStatus) {
+        if self.dialed || !matches!(status, autonat::NatStatus::Private) || self.relays.is_empty() {
+            return;
+        }
+        self.dialed = true;
+
+        tracing::info!(
+            "Private reachability detected, dialing {} configured relay(s)",
+            self.relays.len()
+        );
+        for relay in self.relays.clone() {
+            if let Err(err) = swarm.dial(relay.clone()) {
+                tracing::warn!("Failed to dial relay {relay}: {err}");
+                continue;
+            }
+
+            let circuit_addr = relay.with(core::multiaddr::Protocol::P2pCircuit);
+            if let Err(err) = swarm.listen_on(circuit_addr.clone()) {
+                tracing::warn!("Failed to listen on relayed address {circuit_addr}: {err}");
+            }
+        }
+    }
+}
+
+/// Registers with a rendezvous point and discovers other DHT peers through
+/// it, re-registering on a timer since registrations expire.
+struct RendezvousManager {
+    point: Option<(PeerId, Multiaddr)>,
+    namespace: rendezvous::Namespace,
+}
+
+impl RendezvousManager {
+    fn new(point: Option<Multiaddr>, namespace: String) -> Self {
+        let point = point.map(|addr| {
+            let peer_id = extract_peer_id(&addr).expect("rendezvous address must include a peer id");
+            (peer_id, addr)
+        });
+        let namespace = rendezvous::Namespace::new(namespace).expect("valid rendezvous namespace");
+        Self { point, namespace }
+    }
+
+    fn dial_if_configured(&self, swarm: &mut Swarm<Behaviour>) {
+        if let Some((_, addr)) = &self.point {
+            tracing::info!("Dialing rendezvous point {addr}");
+            if let Err(err) = swarm.dial(addr.clone()) {
+                tracing::warn!("Failed to dial rendezvous point {addr}: {err}");
+            }
+        }
+    }
+
+    fn on_connection_established(&self, swarm: &mut Swarm<Behaviour>, peer_id: PeerId) {
+        if self.point.as_ref().map(|(id, _)| *id) == Some(peer_id) {
+            self.register_and_discover(swarm, peer_id);
+        }
+    }
+
+    fn reregister(&self, swarm: &mut Swarm<Behaviour>) {
+        if let Some((peer_id, _)) = self.point {
+            self.register_and_discover(swarm, peer_id);
+        }
+    }
+
+    fn register_and_discover(&self, swarm: &mut Swarm<Behaviour>, rendezvous_peer_id: PeerId) {
+        tracing::info!(
+            "Registering in namespace {:?} with rendezvous point {rendezvous_peer_id}",
+            self.namespace
+        );
+        if let Err(err) =
+            swarm
+                .behaviour_mut()
+                .rendezvous
+                .register(self.namespace.clone(), rendezvous_peer_id, None)
+        {
+            tracing::warn!("Failed to register with rendezvous point: {err}");
+        }
+
+        swarm.behaviour_mut().rendezvous.discover(
+            Some(self.namespace.clone()),
+            None,
+            None,
+            rendezvous_peer_id,
+        );
+    }
+
+    fn on_discovered(&self, swarm: &mut Swarm<Behaviour>, registrations: Vec<rendezvous::Registration>) {
+        for registration in registrations {
+            let peer_id = registration.record.peer_id();
+            for addr in registration.record.addresses() {
+                tracing::info!("Adding address to Kademlia from rendezvous: {addr}");
+                swarm.behaviour_mut().kad.add_address(&peer_id, addr.clone());
+            }
+        }
+    }
+}
+
+/// Drives periodic Kademlia bootstrap and random-walk refreshes so the
+/// routing table doesn't degrade as peers churn.
+struct BootstrapManager {
+    bootstrapped_once: bool,
+    disable_random_walk: bool,
+}
+
+impl BootstrapManager {
+    fn new(disable_random_walk: bool) -> Self {
+        Self {
+            bootstrapped_once: false,
+            disable_random_walk,
+        }
+    }
+
+    /// Runs the one-shot bootstrap shortly after the first connection, since
+    /// `kad.bootstrap()` requires at least one peer in the routing table.
+    fn on_connection_established(&mut self, swarm: &mut Swarm<Behaviour>) {
+        if self.bootstrapped_once {
+            return;
+        }
+        self.bootstrapped_once = true;
+
+        tracing::info!("First connection established, bootstrapping Kademlia");
+        if let Err(err) = swarm.behaviour_mut().kad.bootstrap() {
+            tracing::warn!("Failed to bootstrap Kademlia: {err}");
+        }
+    }
+
+    fn on_tick(&self, swarm: &mut Swarm<Behaviour>) {
+        tracing::debug!("Running scheduled Kademlia bootstrap");
+        if let Err(err) = swarm.behaviour_mut().kad.bootstrap() {
+            tracing::debug!("Skipping scheduled bootstrap, no known peers yet: {err}");
+        }
+
+        if !self.disable_random_walk {
+            let random_peer = PeerId::random();
+            tracing::debug!("Issuing random-walk query for {random_peer}");
+            swarm.behaviour_mut().kad.get_closest_peers(random_peer);
+        }
+    }
+}
+
+/// DHT-specific counters not already covered by `libp2p::metrics::Metrics`,
+/// derived from `on_query_result`/`on_inbound_request`.
+#[derive(Clone, Default)]
+struct DhtMetrics {
+    put_record_ok: Counter,
+    put_record_err: Counter,
+    get_record_ok: Counter,
+    get_record_err: Counter,
+    inbound_requests: Counter,
+}
+
+impl DhtMetrics {
+    fn register(registry: &mut Registry) -> Self {
+        let metrics = Self::default();
+        registry.register(
+            "kad_put_record_ok",
+            "Number of successful outbound PutRecord queries",
+            metrics.put_record_ok.clone(),
+        );
+        registry.register(
+            "kad_put_record_err",
+            "Number of failed outbound PutRecord queries",
+            metrics.put_record_err.clone(),
+        );
+        registry.register(
+            "kad_get_record_ok",
+            "Number of successful outbound GetRecord queries",
+            metrics.get_record_ok.clone(),
+        );
+        registry.register(
+            "kad_get_record_err",
+            "Number of failed outbound GetRecord queries",
+            metrics.get_record_err.clone(),
+        );
+        registry.register(
+            "kad_inbound_requests",
+            "Number of inbound Kademlia requests received",
+            metrics.inbound_requests.clone(),
+        );
+        metrics
+    }
+}
+
+async fn metrics_handler(State(registry): State<Arc<Registry>>) -> String {
+    let mut buffer = String::new();
+    encode(&mut buffer, &registry).expect("metrics encode to a string never fails");
+    buffer
+}
+
+fn spawn_metrics_server(addr: SocketAddr, registry: Registry) {
+    let registry = Arc::new(registry);
+    tokio::spawn(async move {
+        let app = Router::new()
+            .route("/metrics", get(metrics_handler))
+            .with_state(registry);
+
+        let listener = match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                tracing::error!("Failed to bind metrics listener on {addr}: {err}");
+                return;
+            }
+        };
+
+        tracing::info!("Serving Prometheus metrics on http://{addr}/metrics");
+        if let Err(err) = axum::serve(listener, app).await {
+            tracing::error!("Metrics server stopped unexpectedly: {err}");
+        }
+    });
 }
 
 #[tokio::main]
@@ -37,29 +397,91 @@ async fn main() {
         .init();
 
     let app = App::parse();
+    let mut kad_mode_manager = KadModeManager::new(app.kad_mode);
+    let mut relay_manager = RelayManager::new(app.relays.clone());
+    let rendezvous_manager =
+        RendezvousManager::new(app.rendezvous.clone(), app.rendezvous_namespace.clone());
+    let mut rendezvous_reregister = tokio::time::interval(app.rendezvous_reregister_interval);
+    let mut bootstrap_manager = BootstrapManager::new(app.disable_random_walk);
+    let mut bootstrap_tick = tokio::time::interval(app.bootstrap_interval);
+
+    let mut metrics_registry = Registry::default();
+    let dht_metrics = DhtMetrics::register(&mut metrics_registry);
 
-    let mut swarm = create_swarm(app.bootnodes);
+    let connection_limits = connection_limits::ConnectionLimits::default()
+        .with_max_established_incoming(app.max_established_incoming)
+        .with_max_established_outgoing(app.max_established_outgoing)
+        .with_max_pending_incoming(app.max_pending_incoming)
+        .with_max_pending_outgoing(app.max_pending_outgoing)
+        .with_max_established_per_peer(Some(app.max_per_peer));
+
+    let (mut swarm, metrics) = create_swarm(
+        app.bootnodes,
+        app.kad_mode,
+        connection_limits,
+        &mut metrics_registry,
+    )
+    .await;
     for addr in app.listen_addrs {
         swarm.listen_on(addr).unwrap();
     }
+    rendezvous_manager.dial_if_configured(&mut swarm);
+    spawn_metrics_server(app.metrics_addr, metrics_registry);
 
     loop {
         tokio::select! {
-            event = swarm.select_next_some() => on_swarm_event(&mut swarm, event)
+            event = swarm.select_next_some() => {
+                on_swarm_event(
+                    &mut swarm,
+                    event,
+                    &mut EventContext {
+                        kad_mode_manager: &mut kad_mode_manager,
+                        relay_manager: &mut relay_manager,
+                        rendezvous_manager: &rendezvous_manager,
+                        bootstrap_manager: &mut bootstrap_manager,
+                        metrics: &metrics,
+                        dht_metrics: &dht_metrics,
+                    },
+                )
+            }
+            _ = rendezvous_reregister.tick() => rendezvous_manager.reregister(&mut swarm),
+            _ = bootstrap_tick.tick() => bootstrap_manager.on_tick(&mut swarm),
         }
     }
 }
 
+/// Bundles the long-lived managers and metrics handles threaded through
+/// event dispatch, so `on_swarm_event`/`on_behaviour_event` take one context
+/// argument instead of growing another positional parameter per manager.
+struct EventContext<'a> {
+    kad_mode_manager: &'a mut KadModeManager,
+    relay_manager: &'a mut RelayManager,
+    rendezvous_manager: &'a RendezvousManager,
+    bootstrap_manager: &'a mut BootstrapManager,
+    metrics: &'a Metrics,
+    dht_metrics: &'a DhtMetrics,
+}
+
 #[derive(NetworkBehaviour)]
 struct Behaviour {
     ping: ping::Behaviour,
     identify: identify::Behaviour,
     kad: kad::Behaviour<kad::store::MemoryStore>,
     autonat: autonat::Behaviour,
+    relay_client: relay::client::Behaviour,
+    dcutr: dcutr::Behaviour,
+    rendezvous: rendezvous::client::Behaviour,
+    connection_limits: connection_limits::Behaviour,
 }
 
 impl Behaviour {
-    fn new(keypair: Keypair, bootnodes: Vec<Multiaddr>) -> Self {
+    fn new(
+        keypair: Keypair,
+        bootnodes: Vec<Multiaddr>,
+        kad_mode: KadModeArg,
+        relay_client: relay::client::Behaviour,
+        connection_limits: connection_limits::ConnectionLimits,
+    ) -> Self {
         let ping = ping::Behaviour::new(ping::Config::default());
 
         let identify = identify::Behaviour::new(identify::Config::new(
@@ -70,7 +492,14 @@ impl Behaviour {
         let local_peer_id = keypair.public().to_peer_id();
         let mut kad =
             kad::Behaviour::new(local_peer_id, kad::store::MemoryStore::new(local_peer_id));
-        kad.set_mode(Some(kad::Mode::Server));
+        // `auto`/`client` both start out as `Client`; only `auto` later moves
+        // to `Server` once reachability is confirmed. `server` is pinned
+        // regardless of reachability.
+        let initial_mode = match kad_mode {
+            KadModeArg::Server => kad::Mode::Server,
+            KadModeArg::Auto | KadModeArg::Client => kad::Mode::Client,
+        };
+        kad.set_mode(Some(initial_mode));
 
         for node in bootnodes {
             tracing::info!("Adding address to Kademlia: {node}");
@@ -78,64 +507,122 @@ impl Behaviour {
         }
 
         let autonat = autonat::Behaviour::new(local_peer_id, autonat::Config::default());
+        let dcutr = dcutr::Behaviour::new(local_peer_id);
+        let rendezvous = rendezvous::client::Behaviour::new(keypair);
+        let connection_limits = connection_limits::Behaviour::new(connection_limits);
 
         Self {
             ping,
             identify,
             kad,
             autonat,
+            relay_client,
+            dcutr,
+            rendezvous,
+            connection_limits,
         }
     }
 }
 
-fn create_swarm(bootnodes: Vec<Multiaddr>) -> Swarm<Behaviour> {
+async fn create_swarm(
+    bootnodes: Vec<Multiaddr>,
+    kad_mode: KadModeArg,
+    connection_limits: connection_limits::ConnectionLimits,
+    metrics_registry: &mut Registry,
+) -> (Swarm<Behaviour>, Metrics) {
     let identity = identity::Keypair::generate_ed25519();
-    let local_peer_id = identity.public().to_peer_id();
-    tracing::info!("Local peer id: {local_peer_id}");
-
-    let noise_config = noise::Config::new(&identity).unwrap(); // TODO: proper error handling
-    let muxer_config = yamux::Config::default();
-
-    let tcp_config = tcp::Config::new();
-    let tcp_transport = tcp::tokio::Transport::new(tcp_config.clone());
-
-    let ws = websocket::WsConfig::new(tcp::tokio::Transport::new(tcp_config));
-    let tcp_ws_transport = tcp_transport
-        .or_transport(ws)
-        .upgrade(core::upgrade::Version::V1Lazy)
-        .authenticate(noise_config)
-        .multiplex(muxer_config)
-        .boxed();
-
-    let local_peer_id = identity.public().to_peer_id();
-
-    Swarm::new(
-        tcp_ws_transport,
-        Behaviour::new(identity, bootnodes),
-        local_peer_id,
-        swarm::Config::with_tokio_executor(),
-    )
+    tracing::info!("Local peer id: {}", identity.public().to_peer_id());
+
+    let swarm = libp2p::SwarmBuilder::with_existing_identity(identity)
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::new(),
+            noise::Config::new,
+            yamux::Config::default,
+        )
+        .unwrap() // TODO: proper error handling
+        .with_quic()
+        .with_websocket(noise::Config::new, yamux::Config::default)
+        .await
+        .unwrap()
+        .with_relay_client(noise::Config::new, yamux::Config::default)
+        .unwrap()
+        .with_bandwidth_metrics(metrics_registry)
+        .with_behaviour(|keypair, relay_client| {
+            Behaviour::new(
+                keypair.clone(),
+                bootnodes,
+                kad_mode,
+                relay_client,
+                connection_limits,
+            )
+        })
+        .unwrap()
+        .build();
+
+    let metrics = Metrics::new(metrics_registry);
+    (swarm, metrics)
 }
 
-fn on_swarm_event(swarm: &mut Swarm<Behaviour>, event: SwarmEvent<BehaviourEvent>) {
+fn on_swarm_event(
+    swarm: &mut Swarm<Behaviour>,
+    event: SwarmEvent<BehaviourEvent>,
+    ctx: &mut EventContext,
+) {
+    ctx.metrics.record(&event);
+
     match event {
         SwarmEvent::NewListenAddr { address, .. } => {
             tracing::debug!("New listen address: {address}");
         }
         SwarmEvent::ExternalAddrConfirmed { address } => {
-            tracing::debug!("Local external address confirmed: {address}")
+            tracing::debug!("Local external address confirmed: {address}");
+            ctx.kad_mode_manager
+                .on_nat_status(swarm, autonat::NatStatus::Public(address.clone()));
+            ctx.relay_manager
+                .on_nat_status(swarm, autonat::NatStatus::Public(address));
+        }
+        SwarmEvent::ExternalAddrExpired { address } => {
+            tracing::debug!("Local external address expired: {address}");
+            ctx.kad_mode_manager
+                .on_nat_status(swarm, autonat::NatStatus::Private);
+            ctx.relay_manager
+                .on_nat_status(swarm, autonat::NatStatus::Private);
         }
         SwarmEvent::NewExternalAddrOfPeer { peer_id, address } => {
             tracing::debug!("External address confirmed: {address} for {peer_id}")
         }
-        SwarmEvent::Behaviour(event) => on_behaviour_event(swarm, event),
+        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+            ctx.rendezvous_manager.on_connection_established(swarm, peer_id);
+            ctx.bootstrap_manager.on_connection_established(swarm);
+        }
+        SwarmEvent::IncomingConnectionError { send_back_addr, error, .. } => {
+            if matches!(&error, ListenError::Denied { cause } if cause.downcast_ref::<connection_limits::Exceeded>().is_some())
+            {
+                tracing::warn!("Rejected inbound connection from {send_back_addr}: connection limit exceeded");
+            } else {
+                tracing::debug!("Rejected inbound connection from {send_back_addr}: {error}");
+            }
+        }
+        SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+            if matches!(&error, DialError::Denied { cause } if cause.downcast_ref::<connection_limits::Exceeded>().is_some())
+            {
+                tracing::warn!("Rejected outbound connection to {peer_id:?}: connection limit exceeded");
+            } else {
+                tracing::debug!("Rejected outbound connection to {peer_id:?}: {error}");
+            }
+        }
+        SwarmEvent::Behaviour(event) => on_behaviour_event(swarm, event, ctx),
         _ => tracing::debug!("Received unhandled event: {event:?}"),
     }
 }
 
-fn on_behaviour_event(swarm: &mut Swarm<Behaviour>, event: BehaviourEvent) {
+fn on_behaviour_event(swarm: &mut Swarm<Behaviour>, event: BehaviourEvent, ctx: &mut EventContext) {
+    let metrics = ctx.metrics;
+    let dht_metrics = ctx.dht_metrics;
     match event {
         BehaviourEvent::Identify(event) => {
+            metrics.record(&event);
             match event {
                 identify::Event::Received { peer_id, info, .. } => {
                     tracing::info!("Received identify event with info: {info:?}");
@@ -173,30 +660,124 @@ fn on_behaviour_event(swarm: &mut Swarm<Behaviour>, event: BehaviourEvent) {
                 _ => tracing::debug!("Received unhandled identify event: {event:?}"),
             };
         }
-        BehaviourEvent::Kad(event) => match event {
-            kad::Event::OutboundQueryProgressed { result, .. } => on_query_result(result),
-            kad::Event::InboundRequest { request } => on_inbound_request(request),
-            _ => tracing::debug!("Received unhandled kadmelia event: {event:?}"),
+        BehaviourEvent::Kad(event) => {
+            metrics.record(&event);
+            match event {
+                kad::Event::OutboundQueryProgressed { result, step, .. } => {
+                    on_query_result(result, &step, dht_metrics)
+                }
+                kad::Event::InboundRequest { request } => {
+                    on_inbound_request(request, dht_metrics)
+                }
+                _ => tracing::debug!("Received unhandled kadmelia event: {event:?}"),
+            }
+        }
+        // `libp2p::metrics::Metrics` has no `Recorder` impl for `autonat::Event`,
+        // so there's nothing to record here.
+        BehaviourEvent::Autonat(event) => match event {
+            autonat::Event::StatusChanged { old, new } => {
+                tracing::info!("AutoNAT status changed from {old:?} to {new:?}");
+                ctx.kad_mode_manager.on_nat_status(swarm, new.clone());
+                ctx.relay_manager.on_nat_status(swarm, new);
+            }
+            _ => tracing::debug!("Received unhandled autonat event: {event:?}"),
+        },
+        BehaviourEvent::Ping(event) => {
+            metrics.record(&event);
+            tracing::debug!("Received ping event: {event:?}");
+        }
+        // `Metrics` only implements `Recorder` for the relay *server* event type,
+        // not `relay::client::Event`, so there's nothing to record here.
+        BehaviourEvent::RelayClient(event) => {
+            tracing::info!("Relay client event: {event:?}");
+        }
+        BehaviourEvent::Dcutr(event) => {
+            metrics.record(&event);
+            match event.result {
+                Ok(connection_id) => {
+                    tracing::info!(
+                        "Hole punch with {} succeeded on connection {connection_id:?}",
+                        event.remote_peer_id
+                    );
+                }
+                Err(err) => {
+                    tracing::warn!("Hole punch with {} failed: {err}", event.remote_peer_id);
+                }
+            }
+        }
+        // Likewise, no `Recorder` impl exists for `rendezvous::client::Event`.
+        BehaviourEvent::Rendezvous(event) => match event {
+            rendezvous::client::Event::Discovered { registrations, .. } => {
+                ctx.rendezvous_manager.on_discovered(swarm, registrations);
+            }
+            rendezvous::client::Event::Registered { namespace, ttl, .. } => {
+                tracing::info!("Registered with rendezvous point in {namespace} for {ttl}s");
+            }
+            rendezvous::client::Event::RegisterFailed { error, .. } => {
+                tracing::warn!("Failed to register with rendezvous point: {error:?}");
+            }
+            _ => tracing::debug!("Received unhandled rendezvous event: {event:?}"),
         },
         _ => tracing::debug!("Received unhandled behaviour event: {event:?}"),
     }
 }
 
-fn on_query_result(result: QueryResult) {
+/// `result` fires once per responding peer for multi-response queries like
+/// `GetRecord`, so counters are only incremented on `step.is_last()` — the
+/// final progress event for the logical query — to avoid inflating them.
+fn on_query_result(result: QueryResult, step: &kad::ProgressStep, dht_metrics: &DhtMetrics) {
     match result {
         kad::QueryResult::GetRecord(get_record_ok) => match get_record_ok {
-            Ok(ok) => tracing::info!("Successful GetRecord: {ok:?}"),
-            Err(err) => tracing::error!("Failed GetRecord: {err:?}"),
+            Ok(ok) => {
+                if step.is_last() {
+                    dht_metrics.get_record_ok.inc();
+                }
+                tracing::info!("Successful GetRecord: {ok:?}");
+            }
+            Err(err) => {
+                if step.is_last() {
+                    dht_metrics.get_record_err.inc();
+                }
+                tracing::error!("Failed GetRecord: {err:?}");
+            }
         },
         kad::QueryResult::PutRecord(put_record_ok) => match put_record_ok {
-            Ok(ok) => tracing::info!("Successful PutRecord: {ok:?}"),
-            Err(err) => tracing::error!("Failed PutRecord: {err:?}"),
+            Ok(ok) => {
+                if step.is_last() {
+                    dht_metrics.put_record_ok.inc();
+                }
+                tracing::info!("Successful PutRecord: {ok:?}");
+            }
+            Err(err) => {
+                if step.is_last() {
+                    dht_metrics.put_record_err.inc();
+                }
+                tracing::error!("Failed PutRecord: {err:?}");
+            }
+        },
+        kad::QueryResult::Bootstrap(bootstrap_result) => match bootstrap_result {
+            Ok(ok) => tracing::info!(
+                "Bootstrap progressed via {}, {} buckets remaining",
+                ok.peer,
+                ok.num_remaining
+            ),
+            Err(err) => tracing::error!("Bootstrap failed: {err:?}"),
+        },
+        kad::QueryResult::GetClosestPeers(get_closest_peers_result) => match get_closest_peers_result
+        {
+            Ok(ok) => tracing::info!(
+                "Random-walk refresh found {} peers near {:?}",
+                ok.peers.len(),
+                ok.key
+            ),
+            Err(err) => tracing::error!("Random-walk refresh failed: {err:?}"),
         },
         _ => tracing::debug!("Received unhandled QueryResult: {result:?}"),
     }
 }
 
-fn on_inbound_request(request: InboundRequest) {
+fn on_inbound_request(request: InboundRequest, dht_metrics: &DhtMetrics) {
+    dht_metrics.inbound_requests.inc();
     match request {
         kad::InboundRequest::GetRecord { .. } => {
             tracing::info!("Received GetRecord request: {request:?}")